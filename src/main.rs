@@ -1,8 +1,24 @@
 #![feature(asm)]
 #![feature(naked_functions)]
+#[cfg(feature = "growable-stack")]
+extern crate libc;
+
+mod stack;
+
+use stack::Stack;
+use std::cell::RefCell;
 use std::ptr;
+use std::rc::Rc;
 
-const DEFAULT_STACK_SIZE: usize = 1024 * 1024* 2;
+// With `growable-stack` enabled, a stack starts at this size and grows on
+// demand, so there's no reason to eagerly reserve megabytes of real memory
+// for it up front the way the fixed-`Vec` path does.
+#[cfg(feature = "growable-stack")]
+const DEFAULT_STACK_SIZE: usize = 1024 * 8;
+#[cfg(all(target_arch = "x86_64", not(feature = "growable-stack")))]
+const DEFAULT_STACK_SIZE: usize = 1024 * 1024 * 2;
+#[cfg(all(target_arch = "riscv64", not(feature = "growable-stack")))]
+const DEFAULT_STACK_SIZE: usize = 1024 * 4;
 const MAX_THREADS: usize = 4;
 static mut RUNTIME: usize = 0;
 
@@ -20,13 +36,31 @@ enum State {
 
 struct Thread {
     id: usize,
-    stack: Vec<u8>,
+    stack: Stack,
     ctx: ThreadContext,
     state: State,
+    // The closure a spawned thread should run, boxed because a `Box<dyn
+    // FnOnce()>` is a fat pointer and won't fit in the single thin return
+    // address `switch` can `ret` into. `call_task` picks it up via
+    // `RUNTIME` and runs it on the thread's own stack.
+    task: Option<Box<dyn FnOnce()>>,
+}
+
+/// A handle to a spawned thread's eventual result, in the spirit of
+/// `std::thread::JoinHandle`. Join it to retrieve the value, or call
+/// `detach` (or just drop it) to let the thread run to completion on its
+/// own.
+pub struct JoinHandle<T> {
+    result: Rc<RefCell<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn detach(self) {}
 }
 
 #[derive(Debug, Default)]
-#[repr(C)] 
+#[repr(C)]
+#[cfg(all(target_arch = "x86_64", not(target_os = "windows")))]
 struct ThreadContext {
     rsp: u64,
     r15: u64,
@@ -35,16 +69,79 @@ struct ThreadContext {
     r12: u64,
     rbx: u64,
     rbp: u64,
-    win_nt_tib: u128,
+}
+
+// Windows x64 treats more registers as non-volatile than the System V ABI:
+// `rdi`/`rsi` are callee-saved (Linux uses them for the first two argument
+// registers, so they don't need saving there), and all 128 bits of
+// XMM6-XMM15 must survive a call too. Each XMM register is stored as
+// `[u64; 2]` rather than `u128` so the field lines up on an 8-byte boundary
+// instead of forcing 16-byte alignment onto the whole struct. We also save
+// the stack bounds out of the Thread Information Block (`%gs:0x08` is the
+// stack base, `%gs:0x10` the stack limit) so Windows' guard-page stack
+// checks and SEH keep working across a switch.
+#[derive(Debug, Default)]
+#[repr(C)]
+#[cfg(all(target_arch = "x86_64", target_os = "windows"))]
+struct ThreadContext {
+    rsp: u64,
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    xmm6: [u64; 2],
+    xmm7: [u64; 2],
+    xmm8: [u64; 2],
+    xmm9: [u64; 2],
+    xmm10: [u64; 2],
+    xmm11: [u64; 2],
+    xmm12: [u64; 2],
+    xmm13: [u64; 2],
+    xmm14: [u64; 2],
+    xmm15: [u64; 2],
+    stack_base: u64,
+    stack_limit: u64,
+}
+
+// The RISC-V context is the return address register `ra`, the stack
+// pointer and the twelve callee-saved `s`-registers (`s0`-`s11`).
+// Unlike x86-64's "push a return address and let `ret` land on it" trick,
+// `ra` is a plain register here, so `switch` always reloads it the same
+// way whether this is the thread's first run or a resume: `spawn` seeds it
+// with `call_task`'s address up front, and every subsequent yield
+// overwrites it with wherever this thread actually suspended.
+#[derive(Debug, Default)]
+#[repr(C)]
+#[cfg(target_arch = "riscv64")]
+struct ThreadContext {
+    ra: u64,
+    sp: u64,
+    s0: u64,
+    s1: u64,
+    s2: u64,
+    s3: u64,
+    s4: u64,
+    s5: u64,
+    s6: u64,
+    s7: u64,
+    s8: u64,
+    s9: u64,
+    s10: u64,
+    s11: u64,
 }
 
 impl Thread {
     fn new(id: usize) -> Self {
         Thread {
             id,
-            stack: vec![0_u8; DEFAULT_STACK_SIZE],
+            stack: Stack::new(DEFAULT_STACK_SIZE),
             ctx: ThreadContext::default(),
             state: State::Available,
+            task: None,
         }
     }
 }
@@ -53,9 +150,10 @@ impl Runtime {
     pub fn new() -> Self {
         let base_thread = Thread {
             id: 0,
-            stack: vec![0_u8; DEFAULT_STACK_SIZE],
+            stack: Stack::new(DEFAULT_STACK_SIZE),
             ctx: ThreadContext::default(),
             state: State::Running,
+            task: None,
         };
 
         let mut threads = vec![base_thread];
@@ -114,26 +212,116 @@ impl Runtime {
         true
     }
 
-    pub fn spawn(&mut self, f: fn()) {
+    /// Spawns `f` on the next available thread and returns a handle that can
+    /// be joined to retrieve its result, or detached to let it run
+    /// unsupervised.
+    pub fn spawn<F, T>(&mut self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + 'static,
+        T: 'static,
+    {
+        let result = Rc::new(RefCell::new(None));
+        let result_for_task = Rc::clone(&result);
+
         let available = self
             .threads
             .iter_mut()
             .find(|t| t.state == State::Available)
             .expect("no available thread.");
 
+        available.task = Some(Box::new(move || {
+            *result_for_task.borrow_mut() = Some(f());
+        }));
+
         let size = available.stack.len();
-        let s_ptr = available.stack.as_mut_ptr();
+        let s_ptr = available.stack.base_ptr();
 
+        // The System V and Windows x64 ABIs both require rsp % 16 == 0 at a
+        // `call` site, i.e. rsp % 16 == 8 on entry to the called function.
+        // `stack.base_ptr()` is only byte-aligned, so the top of the
+        // stack isn't necessarily 16-byte aligned even though `size` is;
+        // round it down explicitly before carving out the two return-
+        // address slots; otherwise an optimized `call_task` that spills to
+        // the stack with `movaps` will fault on the misaligned access.
+        #[cfg(target_arch = "x86_64")]
         unsafe {
-            ptr::write(s_ptr.offset((size - 8) as isize) as *mut u64, guard as u64);
-            ptr::write(s_ptr.offset((size - 16) as isize) as *mut u64, f as u64);
-            available.ctx.rsp = s_ptr.offset((size - 16) as isize) as u64;
+            let stack_top = s_ptr.offset(size as isize) as u64;
+            let aligned_top = stack_top & !0xf;
+            ptr::write((aligned_top - 8) as *mut u64, guard as u64);
+            ptr::write((aligned_top - 16) as *mut u64, call_task as u64);
+            available.ctx.rsp = aligned_top - 16;
+
+            // The TIB's stack bounds (restored from `ctx.stack_base`/
+            // `ctx.stack_limit` on every `switch`, see the `ThreadContext`
+            // doc comment) need to describe *this* thread's own stack from
+            // its very first run, or the first switch into it would instead
+            // write out the freshly-`default`ed zeros and stomp Windows'
+            // real bounds for the underlying OS thread.
+            #[cfg(target_os = "windows")]
+            {
+                available.ctx.stack_base = stack_top;
+                available.ctx.stack_limit = s_ptr as u64;
+            }
+        }
+
+        // RISC-V has no "push a return address and let `ret` land on it"
+        // trick here: `switch` always reloads `ra` straight from the
+        // context and `ret`s into it, on both a thread's first run and
+        // every later resume. So the stack bottom never moves once
+        // allocated; we just point `sp` at the top of it (rounded down to
+        // 16 bytes, same as the x86-64 arm above and for the same reason:
+        // `stack.base_ptr()` is only byte-aligned) and seed `ra` with
+        // `call_task`'s address. `call_task` reaches `guard` with an
+        // explicit call rather than the x86-64 stack trick, since there's
+        // no second return-address slot to fall through into here.
+        #[cfg(target_arch = "riscv64")]
+        unsafe {
+            let stack_top = s_ptr.offset(size as isize) as u64;
+            available.ctx.sp = stack_top & !0xf;
+            available.ctx.ra = call_task as u64;
         }
+
         available.state = State::Ready;
+
+        JoinHandle { result }
+    }
+
+    /// Yields until `handle`'s thread has stored its result, then returns it.
+    pub fn join<T>(&mut self, handle: JoinHandle<T>) -> T {
+        loop {
+            if let Some(value) = handle.result.borrow_mut().take() {
+                return value;
+            }
+            self.t_yield();
+        }
     }
 }
 
-#[cfg_attr(any(target_os="windows", target_os="linux"), naked)]
+// `switch` can only `ret` into a thin function pointer, but the boxed task
+// closure is a fat pointer, so it's stashed on the `Thread` instead and
+// `call_task` is the thin address spawn actually puts on the stack (or, on
+// riscv64, into `ctx.ra`). It pulls the closure back out through `RUNTIME`
+// and runs it. On x86-64, `spawn` pushes `guard`'s address as the stack
+// slot right below `call_task`'s, so falling off the end of this function
+// chains into `guard` implicitly via its own `ret` — calling `guard` here
+// too would run it twice. riscv64 has no spare return-address slot to fall
+// through into like that stack trick relies on, so there it's chained
+// explicitly instead.
+fn call_task() {
+    unsafe {
+        let rt_ptr = RUNTIME as *mut Runtime;
+        let rt = &mut *rt_ptr;
+        let task = rt.threads[rt.current]
+            .task
+            .take()
+            .expect("call_task invoked with no pending task");
+        task();
+    }
+    #[cfg(target_arch = "riscv64")]
+    guard();
+}
+
+#[cfg_attr(any(target_os = "windows", target_os = "linux", target_arch = "riscv64"), naked)]
 fn guard() {
     unsafe {
         let rt_ptr = RUNTIME as *mut Runtime;
@@ -151,13 +339,9 @@ pub fn yield_thread() {
 }
 
 // see: https://github.com/rust-lang/rfcs/blob/master/text/1201-naked-fns.md
+#[cfg(all(target_arch = "x86_64", not(target_os = "windows")))]
 #[naked]
 unsafe fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
-
-    // if cfg!(target_os = "windows") {
-
-    // }
-
     asm!("
         movq     $0, %rdi
         movq     %rsp, 0x00(%rdi)
@@ -167,10 +351,6 @@ unsafe fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
         movq     %r12, 0x20(%rdi)
         movq     %rbx, 0x28(%rdi)
         movq     %rbp, 0x30(%rdi)
-        movq     %gs:0x08, %rax
-        movq     %rax, 0x38(%rdi)
-        movq     %gs:0x16, %rax
-        movq     %rax, 0x40(%rdi)
 
         movq     $1, %rsi
         movq     0x00(%rsi), %rsp
@@ -180,10 +360,6 @@ unsafe fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
         movq     0x20(%rsi), %r12
         movq     0x28(%rsi), %rbx
         movq     0x30(%rsi), %rbp
-        movq     0x38(%rdi), %rax
-        movq     %rax, %gs:0x08
-        movq     0x40(%rdi), %rax
-        movq     %rax, %gs:0x16
 
         retq
         "
@@ -195,24 +371,206 @@ unsafe fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
 
 }
 
+// Windows needs the extra non-volatile registers saved/restored (see the
+// `ThreadContext` doc comment above), and the TIB stack-bounds slots fixed
+// up: the previous version of this asm pulled them from `%gs:0x16`, which
+// is wrong — the base is at `%gs:0x08` and the limit at `%gs:0x10`.
+// The save/restore base pointers go through `%rax`/`%rdx`, not `%rdi`/
+// `%rsi`: those two are themselves among the non-volatile registers this
+// asm has to save, so writing `old`/`new` into them before their real
+// values are saved would clobber the very values callers need preserved.
+#[cfg(all(target_arch = "x86_64", target_os = "windows"))]
+#[naked]
+unsafe fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
+    asm!("
+        movq     $0, %rax
+        movq     %rsp, 0x00(%rax)
+        movq     %r15, 0x08(%rax)
+        movq     %r14, 0x10(%rax)
+        movq     %r13, 0x18(%rax)
+        movq     %r12, 0x20(%rax)
+        movq     %rbx, 0x28(%rax)
+        movq     %rbp, 0x30(%rax)
+        movq     %rdi, 0x38(%rax)
+        movq     %rsi, 0x40(%rax)
+        movaps   %xmm6,  0x48(%rax)
+        movaps   %xmm7,  0x58(%rax)
+        movaps   %xmm8,  0x68(%rax)
+        movaps   %xmm9,  0x78(%rax)
+        movaps   %xmm10, 0x88(%rax)
+        movaps   %xmm11, 0x98(%rax)
+        movaps   %xmm12, 0xa8(%rax)
+        movaps   %xmm13, 0xb8(%rax)
+        movaps   %xmm14, 0xc8(%rax)
+        movaps   %xmm15, 0xd8(%rax)
+        movq     %gs:0x08, %rdx
+        movq     %rdx, 0xe8(%rax)
+        movq     %gs:0x10, %rdx
+        movq     %rdx, 0xf0(%rax)
+
+        movq     $1, %rdx
+        movq     0x00(%rdx), %rsp
+        movq     0x08(%rdx), %r15
+        movq     0x10(%rdx), %r14
+        movq     0x18(%rdx), %r13
+        movq     0x20(%rdx), %r12
+        movq     0x28(%rdx), %rbx
+        movq     0x30(%rdx), %rbp
+        movq     0x38(%rdx), %rdi
+        movq     0x40(%rdx), %rsi
+        movaps   0x48(%rdx), %xmm6
+        movaps   0x58(%rdx), %xmm7
+        movaps   0x68(%rdx), %xmm8
+        movaps   0x78(%rdx), %xmm9
+        movaps   0x88(%rdx), %xmm10
+        movaps   0x98(%rdx), %xmm11
+        movaps   0xa8(%rdx), %xmm12
+        movaps   0xb8(%rdx), %xmm13
+        movaps   0xc8(%rdx), %xmm14
+        movaps   0xd8(%rdx), %xmm15
+        movq     0xe8(%rdx), %rax
+        movq     %rax, %gs:0x08
+        movq     0xf0(%rdx), %rax
+        movq     %rax, %gs:0x10
+
+        retq
+        "
+    :
+    :"r"(old), "r"(new)
+    : "rax", "rdx", "rdi", "rsi"
+    : "volatile", "alignstack"
+    );
+
+}
+
+#[cfg(target_arch = "riscv64")]
+#[naked]
+unsafe fn switch(old: *mut ThreadContext, new: *const ThreadContext) {
+    asm!("
+        sd      ra,  0x00($0)
+        sd      sp,  0x08($0)
+        sd      s0,  0x10($0)
+        sd      s1,  0x18($0)
+        sd      s2,  0x20($0)
+        sd      s3,  0x28($0)
+        sd      s4,  0x30($0)
+        sd      s5,  0x38($0)
+        sd      s6,  0x40($0)
+        sd      s7,  0x48($0)
+        sd      s8,  0x50($0)
+        sd      s9,  0x58($0)
+        sd      s10, 0x60($0)
+        sd      s11, 0x68($0)
+
+        ld      sp,  0x08($1)
+        ld      s0,  0x10($1)
+        ld      s1,  0x18($1)
+        ld      s2,  0x20($1)
+        ld      s3,  0x28($1)
+        ld      s4,  0x30($1)
+        ld      s5,  0x38($1)
+        ld      s6,  0x40($1)
+        ld      s7,  0x48($1)
+        ld      s8,  0x50($1)
+        ld      s9,  0x58($1)
+        ld      s10, 0x60($1)
+        ld      s11, 0x68($1)
+        ld      ra,  0x00($1)
+
+        ret
+        "
+    :
+    : "r"(old), "r"(new)
+    :
+    : "volatile", "alignstack"
+    );
+}
+
 fn main() {
     let mut runtime = Runtime::new();
     runtime.init();
-    runtime.spawn(|| {
-        println!("THREAD 1 STARTING");
-        let id = 1;
-        for i in 0..10 {
-            println!("thread: {} counter: {}", id, i);
-            yield_thread();
-        }
-    });
-    runtime.spawn(|| {
-        println!("THREAD 2 STARTING");
-        let id = 2;
-        for i in 0..15 {
-            println!("thread: {} counter: {}", id, i);
-            yield_thread();
-        }
-    });
+
+    // `spawn` now takes `F: FnOnce() -> T + 'static`, so each thread can
+    // just capture its id and counter bound from this loop instead of
+    // hard-coding a `let id = ...;` into every closure body.
+    for (id, count) in [(1, 10), (2, 15)] {
+        runtime.spawn(move || {
+            println!("THREAD {} STARTING", id);
+            for i in 0..count {
+                println!("thread: {} counter: {}", id, i);
+                yield_thread();
+            }
+        });
+    }
+
     runtime.run();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A spawned thread whose body forces the compiler to spill an array to
+    // the stack via vectorized (movaps) loads/stores. Before the 16-byte
+    // alignment fix in `spawn`, this could segfault in release builds
+    // depending on where the backing `Vec<u8>` happened to land.
+    #[test]
+    fn spawn_survives_vectorized_stack_spills() {
+        let mut runtime = Runtime::new();
+        runtime.init();
+        runtime.spawn(|| {
+            let src = [1_u64; 64];
+            let mut dst = [0_u64; 64];
+            dst.copy_from_slice(&src);
+            assert_eq!(dst[63], 1);
+        });
+
+        while runtime.t_yield() {}
+    }
+
+    // Each closure below moves its own `Vec` out of the enclosing scope.
+    // Before `spawn` took arbitrary `FnOnce() -> T + 'static` closures, this
+    // data had to be re-derived inside each thread body (see the old
+    // `let id = 1;` / `let id = 2;` duplication in `main`); this checks that
+    // captured environments stay independent per thread rather than
+    // aliasing through the shared `Thread` slots they're boxed into.
+    #[test]
+    fn spawned_closures_capture_independent_environments() {
+        let mut runtime = Runtime::new();
+        runtime.init();
+
+        let handles: Vec<_> = [10, 20, 30]
+            .iter()
+            .map(|&n| {
+                let data = vec![n; 3];
+                runtime.spawn(move || data.iter().sum::<i32>())
+            })
+            .collect();
+
+        let totals: Vec<i32> = handles.into_iter().map(|h| runtime.join(h)).collect();
+        assert_eq!(totals, vec![30, 60, 90]);
+    }
+
+    // Recurses well past `stack::growable::INITIAL_COMMITTED` (8 KiB),
+    // forcing several guard-page faults and `Stack::grow` calls before this
+    // returns. Each frame's `padding` array is folded into the result so
+    // the compiler can't prove it dead and elide the stack use.
+    #[cfg(feature = "growable-stack")]
+    #[test]
+    fn spawn_grows_stack_past_initial_commit() {
+        fn burn_stack(depth: u32) -> u64 {
+            let padding = [depth as u64; 64];
+            let sum: u64 = padding.iter().sum();
+            if depth == 0 {
+                sum
+            } else {
+                sum + burn_stack(depth - 1)
+            }
+        }
+
+        let mut runtime = Runtime::new();
+        runtime.init();
+        let handle = runtime.spawn(|| burn_stack(4000));
+        assert!(runtime.join(handle) > 0);
+    }
 }
\ No newline at end of file
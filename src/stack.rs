@@ -0,0 +1,227 @@
+//! Thread stack storage.
+//!
+//! By default a `Thread`'s stack is just a fixed-size `Vec<u8>`, which is
+//! what keeps this whole runtime readable in one sitting: `MAX_THREADS *
+//! DEFAULT_STACK_SIZE` is allocated up front and nothing more ever happens.
+//! The downside, as with any fixed-size stack, is that `MAX_THREADS` costs
+//! that memory whether or not the threads ever need it, and a thread that
+//! recurses past its limit overflows silently into whatever memory follows
+//! it on the heap.
+//!
+//! Enabling the `growable-stack` feature swaps in an on-demand stack
+//! instead: `new` reserves `MAX_COMMITTED` bytes of virtual address space
+//! up front via a single `PROT_NONE` `mmap`, of which only the top
+//! `INITIAL_COMMITTED` bytes start out `mprotect`'d read/write. The first
+//! time execution faults into the still-`PROT_NONE` remainder, the guard
+//! handler `mprotect`s more of it read/write in place and resumes.
+//! Crucially, the stack's virtual address range is reserved — and so fixed
+//! — for its entire lifetime; growing it never moves or copies anything,
+//! so a frame pointer, a borrowed-local address, or any other live pointer
+//! into the stack stays valid across a grow without needing to be rebased.
+//! Growth stops once the whole reservation is committed; a fault past that
+//! point is a genuine stack overflow and gets the default disposition
+//! instead of looping forever.
+
+#[cfg(not(feature = "growable-stack"))]
+mod fixed {
+    pub struct Stack(Vec<u8>);
+
+    impl Stack {
+        pub fn new(size: usize) -> Self {
+            Stack(vec![0_u8; size])
+        }
+
+        pub fn base_ptr(&mut self) -> *mut u8 {
+            self.0.as_mut_ptr()
+        }
+
+        pub fn len(&self) -> usize {
+            self.0.len()
+        }
+    }
+}
+
+#[cfg(not(feature = "growable-stack"))]
+pub use fixed::Stack;
+
+#[cfg(feature = "growable-stack")]
+mod growable {
+    use libc::{
+        c_int, c_void, mmap, mprotect, munmap, sigaction, sigaltstack, siginfo_t, sigemptyset,
+        stack_t, MAP_ANON, MAP_PRIVATE, PROT_NONE, PROT_READ, PROT_WRITE, SA_ONSTACK, SA_SIGINFO,
+        SIGBUS, SIGSEGV,
+    };
+    use std::cell::Cell;
+    use std::ptr;
+    use std::sync::Once;
+
+    const PAGE_SIZE: usize = 4096;
+    const INITIAL_COMMITTED: usize = 1024 * 8;
+    // The ceiling on how large a single stack can grow, matching a typical
+    // Linux `ulimit -s` default: deep enough for real recursion, but still
+    // bounded so a genuine infinite-recursion bug dies at this limit
+    // instead of eating arbitrarily more address space forever.
+    const MAX_COMMITTED: usize = 1024 * 1024 * 8;
+    // A guard-page hit faults with `rsp` already inside the `PROT_NONE`
+    // region, so the kernel can't push the signal frame onto it; without an
+    // alternate signal stack installed via `sigaltstack`, that's a double
+    // fault and the process dies before `guard_page_fault` ever runs.
+    const ALT_STACK_SIZE: usize = 1024 * 64;
+
+    /// An on-demand stack: `mapping` points at a `MAX_COMMITTED`-byte
+    /// virtual-memory reservation that is made once in `new` and never
+    /// moved or resized for the life of the `Stack`. Only the top
+    /// `committed` bytes of it are ever `mprotect`'d read/write; the rest
+    /// stays `PROT_NONE` and doubles as the guard region that `grow`
+    /// commits more of on demand. The usable region therefore starts at
+    /// `mapping + (mapping_len - committed)`.
+    pub struct Stack {
+        mapping: *mut u8,
+        mapping_len: usize,
+        committed: usize,
+    }
+
+    impl Stack {
+        pub fn new(initial: usize) -> Self {
+            install_guard_page_handler();
+            let committed = round_up_to_page(initial.max(INITIAL_COMMITTED)).min(MAX_COMMITTED);
+            unsafe {
+                let mapping = mmap(
+                    ptr::null_mut(),
+                    MAX_COMMITTED,
+                    PROT_NONE,
+                    MAP_PRIVATE | MAP_ANON,
+                    -1,
+                    0,
+                ) as *mut u8;
+                assert!(mapping != libc::MAP_FAILED as *mut u8, "mmap failed to reserve a stack");
+                let usable = mapping.add(MAX_COMMITTED - committed);
+                let rc = mprotect(usable as *mut c_void, committed, PROT_READ | PROT_WRITE);
+                assert_eq!(rc, 0, "mprotect failed to commit the initial stack");
+                Stack {
+                    mapping,
+                    mapping_len: MAX_COMMITTED,
+                    committed,
+                }
+            }
+        }
+
+        pub fn base_ptr(&mut self) -> *mut u8 {
+            unsafe { self.mapping.add(self.mapping_len - self.committed) }
+        }
+
+        pub fn len(&self) -> usize {
+            self.committed
+        }
+
+        /// True if `addr` landed in this stack's reserved-but-not-yet-
+        /// committed region, i.e. this is the stack whose owning thread
+        /// just ran off the bottom of its currently committed memory.
+        fn owns_fault(&self, addr: usize) -> bool {
+            let mapping_start = self.mapping as usize;
+            let committed_start = mapping_start + (self.mapping_len - self.committed);
+            addr >= mapping_start && addr < committed_start
+        }
+
+        /// Commits more of the already-reserved mapping read/write,
+        /// doubling the usable region (capped at the full reservation).
+        /// Because the mapping's address range was fixed for good back in
+        /// `new`, this never moves or copies anything, so there is nothing
+        /// to rebase `rsp` — or any other pointer into the stack — onto.
+        /// Returns `false` if the reservation is already fully committed,
+        /// meaning this is a real overflow past `MAX_COMMITTED` rather than
+        /// something growth can fix.
+        fn grow(&mut self) -> bool {
+            if self.committed >= self.mapping_len {
+                return false;
+            }
+            let new_committed = (self.committed * 2).min(self.mapping_len);
+            unsafe {
+                let grow_start = self.mapping.add(self.mapping_len - new_committed);
+                let grow_len = new_committed - self.committed;
+                let rc = mprotect(grow_start as *mut c_void, grow_len, PROT_READ | PROT_WRITE);
+                assert_eq!(rc, 0, "mprotect failed to grow the stack");
+            }
+            self.committed = new_committed;
+            true
+        }
+    }
+
+    impl Drop for Stack {
+        fn drop(&mut self) {
+            unsafe {
+                munmap(self.mapping as *mut c_void, self.mapping_len);
+            }
+        }
+    }
+
+    fn round_up_to_page(size: usize) -> usize {
+        (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+    }
+
+    static INSTALL_HANDLER: Once = Once::new();
+
+    thread_local! {
+        static ALT_STACK_INSTALLED: Cell<bool> = Cell::new(false);
+    }
+
+    // The handler is process-wide, but `sigaltstack` only affects the
+    // calling OS thread, so each thread that builds stacks gets its own.
+    fn install_guard_page_handler() {
+        ALT_STACK_INSTALLED.with(|installed| {
+            if !installed.get() {
+                unsafe { install_alt_stack() };
+                installed.set(true);
+            }
+        });
+        INSTALL_HANDLER.call_once(|| unsafe {
+            let mut action: sigaction = std::mem::zeroed();
+            action.sa_sigaction = guard_page_fault as usize;
+            action.sa_flags = SA_SIGINFO | SA_ONSTACK;
+            sigemptyset(&mut action.sa_mask);
+            sigaction(SIGSEGV, &action, ptr::null_mut());
+            sigaction(SIGBUS, &action, ptr::null_mut());
+        });
+    }
+
+    // Leaked on purpose: the alternate stack needs to live for the rest of
+    // the process, same as the handler it backs.
+    unsafe fn install_alt_stack() {
+        let alt_stack = Box::leak(vec![0_u8; ALT_STACK_SIZE].into_boxed_slice());
+        let ss = stack_t {
+            ss_sp: alt_stack.as_mut_ptr() as *mut c_void,
+            ss_flags: 0,
+            ss_size: ALT_STACK_SIZE,
+        };
+        let rc = sigaltstack(&ss, ptr::null_mut());
+        assert_eq!(rc, 0, "sigaltstack failed to install the guard-page handler's stack");
+    }
+
+    /// Finds the currently-running thread's stack via `RUNTIME` and grows
+    /// it if the fault landed in its not-yet-committed region. Growing
+    /// never moves the stack (see `Stack::grow`), so unlike the first cut
+    /// of this feature there is no `rsp` (or frame pointer, or borrowed
+    /// local) to rebase here — the faulting context is left untouched and
+    /// execution just resumes where it faulted. Anything else (a fault
+    /// that isn't one of our reservations, or one that's already fully
+    /// committed) re-raises the default disposition instead of looping
+    /// forever.
+    extern "C" fn guard_page_fault(sig: c_int, info: *mut siginfo_t, _ctx: *mut c_void) {
+        unsafe {
+            let fault_addr = (*info).si_addr() as usize;
+            let rt = &mut *(crate::RUNTIME as *mut crate::Runtime);
+            let thread = &mut rt.threads[rt.current];
+
+            if thread.stack.owns_fault(fault_addr) && thread.stack.grow() {
+                return;
+            }
+        }
+
+        unsafe {
+            libc::signal(sig, libc::SIG_DFL);
+        }
+    }
+}
+
+#[cfg(feature = "growable-stack")]
+pub use growable::Stack;